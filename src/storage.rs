@@ -0,0 +1,163 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{env, AccountId};
+
+use crate::user::User;
+
+/**
+ * Abstracts wishlist persistence behind reads and writes of `User` records plus the
+ * storage-cost primitives `add_car`/`delete_car` settle against. Lets tests inject an
+ * in-memory backend instead of reaching for `testing_env!`, and keeps the storage-cost
+ * math unit-testable on its own.
+ */
+pub trait WishlistIo {
+    fn read_user(&self, id: &AccountId) -> Option<User>;
+    fn write_user(&mut self, id: &AccountId, user: &User);
+    fn storage_usage(&self) -> u64;
+    fn storage_byte_cost(&self) -> u128;
+}
+
+/**
+ * Balance required to back `usage` bytes of storage at `byte_cost` per byte
+ */
+pub(crate) fn required_balance(byte_cost: u128, usage: u64) -> u128 {
+    byte_cost.checked_mul(usage as u128).expect("Error calculating storage cost")
+}
+
+/**
+ * Amount withdrawable from a ledger balance backing `usage` bytes at `byte_cost` per byte:
+ * whatever exceeds what's currently required, or an explicit `amount` request if given,
+ * panicking when that request exceeds the withdrawable amount. Shared by the NEAR and FT-funded withdrawal paths so their eligibility rules stay
+ * in lockstep.
+ */
+pub(crate) fn compute_withdrawal(byte_cost: u128, usage: u64, balance: u128, amount: Option<u128>) -> u128 {
+    let required = required_balance(byte_cost, usage);
+    let withdrawable = balance.saturating_sub(required);
+    let withdrawal = amount.unwrap_or(withdrawable);
+    assert!(withdrawal <= withdrawable, "Cannot withdraw more than the available balance!");
+    withdrawal
+}
+
+/**
+ * Shared settlement math behind a per-account storage ledger: folds this call's storage
+ * delta into `previous_usage`, credits `incoming_deposit` onto `previous_balance` rather
+ * than sweeping the whole balance down to what's required, and reports the portion of
+ * `incoming_deposit` that wasn't needed to reach the new requirement. Used identically by
+ * the NEAR-funded and FT-funded ledgers, which only differ in which `LookupMap`s they
+ * read/write the result into.
+ *
+ * Returns `(usage, balance, unused)`.
+ */
+pub(crate) fn settle_ledger(
+    byte_cost: u128,
+    initial_storage: u64,
+    current_storage: u64,
+    previous_usage: u64,
+    previous_balance: u128,
+    incoming_deposit: u128,
+) -> (u64, u128, u128) {
+    // Storage delta caused by this call (can be negative, e.g. on delete_car)
+    let delta = current_storage as i64 - initial_storage as i64;
+    let usage = (previous_usage as i64 + delta).max(0) as u64;
+
+    let required = required_balance(byte_cost, usage);
+    let balance = previous_balance + incoming_deposit;
+    assert!(balance >= required, "Insufficient funds!");
+
+    // Only refund the part of *this call's* deposit that wasn't needed to cover the new
+    // requirement; balance already on deposit from earlier calls is left alone
+    let needed_increase = required.saturating_sub(previous_balance);
+    let unused = incoming_deposit.saturating_sub(needed_increase);
+    (usage, balance - unused, unused)
+}
+
+/**
+ * Default `WishlistIo`, backed by a NEAR `LookupMap` and the `env` runtime
+ */
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct NearRuntime {
+    users: LookupMap<AccountId, User>,
+}
+
+impl Default for NearRuntime {
+    fn default() -> Self {
+        Self {
+            users: LookupMap::new(b"c"),
+        }
+    }
+}
+
+impl WishlistIo for NearRuntime {
+    fn read_user(&self, id: &AccountId) -> Option<User> {
+        self.users.get(id)
+    }
+
+    fn write_user(&mut self, id: &AccountId, user: &User) {
+        self.users.insert(id, user);
+    }
+
+    fn storage_usage(&self) -> u64 {
+        env::storage_usage()
+    }
+
+    fn storage_byte_cost(&self) -> u128 {
+        env::storage_byte_cost()
+    }
+}
+
+/**
+ * Lets `Wishlist` hold its `WishlistIo` as a trait object, reconstructing the default
+ * on-chain backend whenever the field is skipped during Borsh deserialization (see
+ * `#[borsh_skip]` on `Wishlist::io`)
+ */
+impl Default for Box<dyn WishlistIo> {
+    fn default() -> Self {
+        Box::new(NearRuntime::default())
+    }
+}
+
+/**
+ * In-memory `WishlistIo` test double, so storage-cost math and persistence can be
+ * exercised without `testing_env!` or a real NEAR runtime
+ */
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    pub struct MockIo {
+        users: HashMap<AccountId, User>,
+        storage_usage: Cell<u64>,
+        storage_byte_cost: u128,
+    }
+
+    impl MockIo {
+        pub fn new(storage_byte_cost: u128, storage_usage: u64) -> Self {
+            Self {
+                storage_byte_cost,
+                storage_usage: Cell::new(storage_usage),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl WishlistIo for MockIo {
+        fn read_user(&self, id: &AccountId) -> Option<User> {
+            self.users.get(id).cloned()
+        }
+
+        fn write_user(&mut self, id: &AccountId, user: &User) {
+            self.users.insert(id.clone(), user.clone());
+        }
+
+        fn storage_usage(&self) -> u64 {
+            self.storage_usage.get()
+        }
+
+        fn storage_byte_cost(&self) -> u128 {
+            self.storage_byte_cost
+        }
+    }
+}
@@ -8,7 +8,7 @@ use crate::vehicle::Vehicle;
  * User structure
  */
 #[near_bindgen]
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[derive(Clone, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct User {
  wishlist: Vec<Vehicle>,
@@ -55,4 +55,27 @@ impl User {
   assert!(size > 0 && index < size, "Invalid car id!");
   self.wishlist.remove(index as usize)
  }
+
+ /**
+  * Restores a previously removed car object at its original position, so later ids
+  * don't shift the way they would if it were appended at the end
+  */
+ pub fn restore_at(&mut self, index: u64, vehicle: Vehicle) {
+  let position = (index as usize).min(self.wishlist.len());
+  self.wishlist.insert(position, vehicle);
+ }
+
+ /**
+  * Number of cars currently in the wishlist
+  */
+ pub fn len(&self) -> u64 {
+  self.wishlist.len() as u64
+ }
+
+ /**
+  * Whether the wishlist currently has no cars
+  */
+ pub fn is_empty(&self) -> bool {
+  self.wishlist.is_empty()
+ }
 }